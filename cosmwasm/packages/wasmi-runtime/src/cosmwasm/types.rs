@@ -0,0 +1,32 @@
+// CosmWasm 1.0 env shape: nanosecond block time, intra-block transaction ordering,
+// and a richer `ContractInfo`. Kept alongside the existing V010/V016 env structs so
+// `env_to_bytes` can pick the wire format the loaded contract was compiled against.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EnvV1 {
+    pub block: BlockInfoV1,
+    pub transaction: Option<TransactionInfoV1>,
+    pub contract: ContractInfoV1,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlockInfoV1 {
+    pub height: u64,
+    pub time: Timestamp,
+    pub chain_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TransactionInfoV1 {
+    pub index: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ContractInfoV1 {
+    pub address: Addr,
+    pub code_hash: String,
+    pub creator: Option<Addr>,
+    pub admin: Option<Addr>,
+}
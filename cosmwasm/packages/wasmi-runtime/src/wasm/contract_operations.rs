@@ -13,8 +13,8 @@ use super::contract_validation::{
     extract_contract_key, generate_encryption_key, validate_contract_key, validate_msg,
     verify_params, ContractKey, CONTRACT_KEY_LENGTH,
 };
-use super::gas::WasmCosts;
-use super::io::encrypt_output;
+use super::gas::{self, WasmCosts};
+use super::io::{encrypt_output, open_reply_params};
 use super::module_cache::create_module_instance;
 use super::runtime::{ContractInstance, ContractOperation, Engine};
 use super::types::{ContractCode, IoNonce, SecretMessage, SigInfo};
@@ -27,8 +27,10 @@ fn deallocate(pointer: *mut c_void);
 fn init(env_ptr: *mut c_void, msg_ptr: *mut c_void) -> *mut c_void
 fn handle(env_ptr: *mut c_void, msg_ptr: *mut c_void) -> *mut c_void
 fn query(msg_ptr: *mut c_void) -> *mut c_void
+fn migrate(env_ptr: *mut c_void, msg_ptr: *mut c_void) -> *mut c_void // optional, only for contracts that opt into migration
+fn reply(env_ptr: *mut c_void, reply_ptr: *mut c_void) -> *mut c_void // optional, only for contracts that dispatch SubMsg
 
-Re `init`, `handle` and `query`: We need to pass `env` & `msg`
+Re `init`, `handle`, `query`, `migrate` and `reply`: We need to pass `env` & `msg`
 down to the wasm implementations, but because they are buffers
 we need to allocate memory regions inside the VM's instance and copy
 `env` & `msg` into those memory regions inside the VM's instance.
@@ -42,6 +44,7 @@ pub fn init(
     env: &[u8],         // blockchain state
     msg: &[u8],         // probably function call and args
     sig_info: &[u8],    // info about signature verification
+    tx_index: u32,      // this tx's position in its block, per the CosmWasm 1.0 TransactionInfo
 ) -> Result<InitSuccess, EnclaveError> {
     let contract_code = ContractCode::new(contract);
 
@@ -94,17 +97,21 @@ pub fn init(
         String::from_utf8_lossy(&validated_msg)
     );
 
+    let wasm_costs = gas::wasm_costs_for_height(env_v010.block.height);
+
     let mut engine = start_engine(
         context,
         gas_limit,
         contract_code,
         &contract_key,
+        &canonical_contract_address,
         ContractOperation::Init,
         secret_msg.nonce,
         secret_msg.user_public_key,
+        wasm_costs,
     )?;
 
-    let contract_env_bytes: Vec<u8> = env_to_bytes(&engine, &env_v010);
+    let contract_env_bytes: Vec<u8> = env_to_bytes(&engine, &env_v010, tx_index);
 
     let wasm_env_ptr = engine.write_to_memory(&contract_env_bytes)?;
     let wasm_msg_ptr = engine.write_to_memory(&validated_msg)?;
@@ -148,6 +155,7 @@ pub fn handle(
     env: &[u8],
     msg: &[u8],
     sig_info: &[u8],
+    tx_index: u32, // this tx's position in its block, per the CosmWasm 1.0 TransactionInfo
 ) -> Result<HandleSuccess, EnclaveError> {
     let contract_code = ContractCode::new(contract);
 
@@ -205,17 +213,21 @@ pub fn handle(
     trace!("successfully authenticated the contract!");
     trace!("handle contract key: {:?}", hex::encode(contract_key));
 
+    let wasm_costs = gas::wasm_costs_for_height(env_v010.block.height);
+
     let mut engine = start_engine(
         context,
         gas_limit,
         contract_code,
         &contract_key,
+        &canonical_contract_address,
         ContractOperation::Handle,
         secret_msg.nonce,
         secret_msg.user_public_key,
+        wasm_costs,
     )?;
 
-    let contract_env_bytes: Vec<u8> = env_to_bytes(&engine, &env_v010);
+    let contract_env_bytes: Vec<u8> = env_to_bytes(&engine, &env_v010, tx_index);
 
     let env_ptr = engine.write_to_memory(&contract_env_bytes)?;
     let msg_ptr = engine.write_to_memory(&validated_msg)?;
@@ -248,12 +260,242 @@ pub fn handle(
     Ok(HandleSuccess { output })
 }
 
+pub fn migrate(
+    context: Ctx,
+    gas_limit: u64,
+    used_gas: &mut u64,
+    contract: &[u8],
+    env: &[u8],
+    msg: &[u8],
+    sig_info: &[u8],
+    tx_index: u32, // this tx's position in its block, per the CosmWasm 1.0 TransactionInfo
+) -> Result<HandleSuccess, EnclaveError> {
+    let contract_code = ContractCode::new(contract);
+
+    let mut env_v010: EnvV010 = serde_json::from_slice(env).map_err(|err| {
+        warn!(
+            "got an error while trying to deserialize env input bytes into json {:?}: {}",
+            String::from_utf8_lossy(&env),
+            err
+        );
+        EnclaveError::FailedToDeserialize
+    })?;
+    env_v010.contract_code_hash = hex::encode(contract_code.hash());
+
+    trace!("migrate env_v010: {:?}", env_v010);
+
+    let parsed_sig_info: SigInfo = serde_json::from_slice(sig_info).map_err(|err| {
+        warn!(
+            "got an error while trying to deserialize env input bytes into json {:?}: {}",
+            String::from_utf8_lossy(&sig_info),
+            err
+        );
+        EnclaveError::FailedToDeserialize
+    })?;
+
+    trace!("migrate input before decryption: {:?}", base64::encode(&msg));
+    let secret_msg = SecretMessage::from_slice(msg)?;
+
+    // Verify env parameters against the signed tx
+    verify_params(&parsed_sig_info, &env_v010, &secret_msg)?;
+
+    let contract_key = extract_contract_key(&env_v010)?;
+
+    let decrypted_msg = secret_msg.decrypt()?;
+
+    let validated_msg = validate_msg(&decrypted_msg, contract_code.hash())?;
+
+    trace!(
+        "migrate input afer decryption: {:?}",
+        String::from_utf8_lossy(&validated_msg)
+    );
+
+    let canonical_contract_address = CanonicalAddr::from_human(&env_v010.contract.address).map_err(|err| {
+        warn!(
+            "got an error while trying to deserialize env_v010.contract.address from bech32 string to bytes {:?}: {}",
+            env_v010.contract.address, err
+        );
+        EnclaveError::FailedToDeserialize
+    })?;
+
+    // The existing state-owning contract key is authenticated against the canonical
+    // address and the code being migrated to, before that code runs under the key.
+    if !validate_contract_key(&contract_key, &(canonical_contract_address.0).0, contract) {
+        warn!("got an error while trying to authenticate the contract before migration");
+        return Err(EnclaveError::FailedContractAuthentication);
+    }
+
+    trace!("successfully authenticated the contract for migration!");
+    trace!("migrate contract key: {:?}", hex::encode(contract_key));
+
+    let wasm_costs = gas::wasm_costs_for_height(env_v010.block.height);
+
+    let mut engine = start_engine(
+        context,
+        gas_limit,
+        contract_code,
+        &contract_key,
+        &canonical_contract_address,
+        ContractOperation::Migrate,
+        secret_msg.nonce,
+        secret_msg.user_public_key,
+        wasm_costs,
+    )?;
+
+    let contract_env_bytes: Vec<u8> = env_to_bytes(&engine, &env_v010, tx_index);
+
+    let env_ptr = engine.write_to_memory(&contract_env_bytes)?;
+    let msg_ptr = engine.write_to_memory(&validated_msg)?;
+
+    // This wrapper is used to coalesce all errors in this block to one object
+    // so we can `.map_err()` in one place for all of them
+    let output = coalesce!(EnclaveError, {
+        let vec_ptr = engine.migrate(env_ptr, msg_ptr)?;
+
+        let output = engine.extract_vector(vec_ptr)?;
+
+        let output = encrypt_output(
+            output,
+            secret_msg.nonce,
+            secret_msg.user_public_key,
+            &canonical_contract_address,
+        )?;
+        Ok(output)
+    })
+    .map_err(|err| {
+        *used_gas = engine.gas_used();
+        err
+    })?;
+
+    *used_gas = engine.gas_used();
+    Ok(HandleSuccess { output })
+}
+
+pub fn reply(
+    context: Ctx,
+    gas_limit: u64,
+    used_gas: &mut u64,
+    contract: &[u8],
+    env: &[u8],
+    msg: &[u8],
+    sig_info: &[u8],
+    reply_params: &[u8],
+    tx_index: u32, // this tx's position in its block, per the CosmWasm 1.0 TransactionInfo
+) -> Result<HandleSuccess, EnclaveError> {
+    let contract_code = ContractCode::new(contract);
+
+    let mut env_v010: EnvV010 = serde_json::from_slice(env).map_err(|err| {
+        warn!(
+            "got an error while trying to deserialize env input bytes into json {:?}: {}",
+            String::from_utf8_lossy(&env),
+            err
+        );
+        EnclaveError::FailedToDeserialize
+    })?;
+    env_v010.contract_code_hash = hex::encode(contract_code.hash());
+
+    trace!("reply env_v010: {:?}", env_v010);
+
+    let parent = open_reply_params(reply_params)?;
+
+    let parsed_sig_info: SigInfo = serde_json::from_slice(sig_info).map_err(|err| {
+        warn!(
+            "got an error while trying to deserialize env input bytes into json {:?}: {}",
+            String::from_utf8_lossy(&sig_info),
+            err
+        );
+        EnclaveError::FailedToDeserialize
+    })?;
+
+    trace!("reply input before decryption: {:?}", base64::encode(&msg));
+    let secret_msg = SecretMessage::from_slice(msg)?;
+
+    // Verify env parameters against the signed tx that carried this reply
+    verify_params(&parsed_sig_info, &env_v010, &secret_msg)?;
+
+    let contract_key = extract_contract_key(&env_v010)?;
+
+    let decrypted_msg = secret_msg.decrypt()?;
+
+    // This is the SubMsgResult the chain got back from the dispatched submessage,
+    // still framed the same way a regular input message is.
+    let validated_msg = validate_msg(&decrypted_msg, contract_code.hash())?;
+
+    trace!(
+        "reply input afer decryption: {:?}",
+        String::from_utf8_lossy(&validated_msg)
+    );
+
+    let canonical_contract_address = CanonicalAddr::from_human(&env_v010.contract.address).map_err(|err| {
+        warn!(
+            "got an error while trying to deserialize env_v010.contract.address from bech32 string to bytes {:?}: {}",
+            env_v010.contract.address, err
+        );
+        EnclaveError::FailedToDeserialize
+    })?;
+
+    if !validate_contract_key(&contract_key, &(canonical_contract_address.0).0, contract) {
+        warn!("got an error while trying to authenticate the contract before reply");
+        return Err(EnclaveError::FailedContractAuthentication);
+    }
+
+    trace!("successfully authenticated the contract for reply!");
+    trace!("reply contract key: {:?}", hex::encode(contract_key));
+
+    let wasm_costs = gas::wasm_costs_for_height(env_v010.block.height);
+
+    // Run the engine with the *parent* nonce/pubkey, not this reply's own transport
+    // encryption, so the execution carries the original caller's IO key.
+    let mut engine = start_engine(
+        context,
+        gas_limit,
+        contract_code,
+        &contract_key,
+        &canonical_contract_address,
+        ContractOperation::Reply,
+        parent.nonce,
+        parent.user_public_key,
+        wasm_costs,
+    )?;
+
+    let contract_env_bytes: Vec<u8> = env_to_bytes(&engine, &env_v010, tx_index);
+
+    let env_ptr = engine.write_to_memory(&contract_env_bytes)?;
+    let reply_ptr = engine.write_to_memory(&validated_msg)?;
+
+    // This wrapper is used to coalesce all errors in this block to one object
+    // so we can `.map_err()` in one place for all of them
+    let output = coalesce!(EnclaveError, {
+        let vec_ptr = engine.reply(env_ptr, reply_ptr)?;
+
+        let output = engine.extract_vector(vec_ptr)?;
+
+        // Encrypt back toward the original caller, using the parent nonce/pubkey,
+        // so the same party that sent the original tx can decrypt the reply output.
+        let output = encrypt_output(
+            output,
+            parent.nonce,
+            parent.user_public_key,
+            &canonical_contract_address,
+        )?;
+        Ok(output)
+    })
+    .map_err(|err| {
+        *used_gas = engine.gas_used();
+        err
+    })?;
+
+    *used_gas = engine.gas_used();
+    Ok(HandleSuccess { output })
+}
+
 pub fn query(
     context: Ctx,
     gas_limit: u64,
     used_gas: &mut u64,
     contract: &[u8],
     msg: &[u8],
+    block_height: u64, // current chain tip height, so query's gas schedule matches in-flight txs
 ) -> Result<QuerySuccess, EnclaveError> {
     let contract_code = ContractCode::new(contract);
 
@@ -278,14 +520,20 @@ pub fn query(
     );
     let validated_msg = validate_msg(&decrypted_msg, contract_code.hash())?;
 
+    // Queries don't carry their own env, so look the schedule up against the chain tip
+    // height passed in by the caller, the same height init/handle at this tip would see.
+    let wasm_costs = gas::wasm_costs_for_height(block_height);
+
     let mut engine = start_engine(
         context,
         gas_limit,
         contract_code,
         &contract_key,
+        &CanonicalAddr(Binary(Vec::new())), // Not used for queries
         ContractOperation::Query,
         secret_msg.nonce,
         secret_msg.user_public_key,
+        wasm_costs,
     )?;
 
     let msg_ptr = engine.write_to_memory(&validated_msg)?;
@@ -319,14 +567,13 @@ fn start_engine(
     gas_limit: u64,
     contract_code: ContractCode,
     contract_key: &ContractKey,
+    contract_addr: &CanonicalAddr,
     operation: ContractOperation,
     nonce: IoNonce,
     user_public_key: Ed25519PublicKey,
+    wasm_costs: WasmCosts,
 ) -> Result<Engine, EnclaveError> {
-    let module = create_module_instance(contract_code)?;
-
-    // Set the gas costs for wasm op-codes (there is an inline stack_height limit in WasmCosts)
-    let wasm_costs = WasmCosts::default();
+    let module = create_module_instance(contract_code, &wasm_costs)?;
 
     let contract_instance = ContractInstance::new(
         context,
@@ -334,15 +581,16 @@ fn start_engine(
         gas_limit,
         wasm_costs,
         *contract_key,
+        contract_addr.clone(),
         operation,
         nonce,
         user_public_key,
-    );
+    )?;
 
     Ok(Engine::new(contract_instance, module))
 }
 
-fn env_to_bytes(engine: &Engine, env_v010: &mut EnvV010) -> Vec<u8> {
+fn env_to_bytes(engine: &Engine, env_v010: &mut EnvV010, tx_index: u32) -> Vec<u8> {
     match engine.contract_instance.cosmwasm_api_version {
         CosmWasmApiVersion::V010 => {
             // contract_key is irrelevant inside the contract
@@ -377,5 +625,33 @@ fn env_to_bytes(engine: &Engine, env_v010: &mut EnvV010) -> Vec<u8> {
                 EnclaveError::FailedToSerialize
             })?
         }
+        CosmWasmApiVersion::V1 => {
+            let env_v1 = EnvV1 {
+                block: BlockInfoV1 {
+                    height: env_v010.block.height,
+                    // V010 only carries second resolution; promote to nanoseconds for the 1.0 env.
+                    time: Timestamp::from_nanos(env_v010.block.time.saturating_mul(1_000_000_000)),
+                    chain_id: env_v010.block.chain_id.clone(),
+                },
+                // `tx_index` is this tx's position within its block, as tracked by the
+                // chain outside the enclave; it's what lets a 1.0 contract tell two txs
+                // in the same block apart for ordering/idempotency purposes.
+                transaction: Some(TransactionInfoV1 { index: tx_index }),
+                contract: ContractInfoV1 {
+                    address: Addr(env_v010.contract.address.0.clone()),
+                    code_hash: env_v010.contract_code_hash.clone(),
+                    creator: None,
+                    admin: None,
+                },
+            };
+
+            serde_json::to_vec(&env_v1).map_err(|err| {
+                warn!(
+                    "got an error while trying to serialize env_v010 (cosmwasm v1.0) into bytes {:?}: {}",
+                    env_v1, err
+                );
+                EnclaveError::FailedToSerialize
+            })?
+        }
     }
 }
@@ -0,0 +1,133 @@
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+/// Per-op-code gas prices fed into the wasm instrumentation pass, plus the inline
+/// stack-height limit enforced alongside it. Governance can schedule a new table to
+/// take effect at a future block height without an enclave upgrade.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WasmCosts {
+    pub regular: u32,
+    pub div: u32,
+    pub mul: u32,
+    pub mem: u32,
+    pub stack_height_limit: u32,
+}
+
+impl Default for WasmCosts {
+    fn default() -> Self {
+        Self {
+            regular: 1,
+            div: 16,
+            mul: 4,
+            mem: 2,
+            stack_height_limit: 65536,
+        }
+    }
+}
+
+/// A `WasmCosts` table scheduled to take effect from `activation_height` onward.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ScheduledWasmCosts {
+    pub activation_height: u64,
+    pub costs: WasmCosts,
+}
+
+lazy_static! {
+    // Seeded with the pre-CosmWasm-1.0 defaults so the enclave has a sane schedule
+    // before the host ever calls `set_wasm_cost_schedule`; `update_consensus_params`
+    // (outside this crate) replaces it whenever the gov module's wasm params change.
+    static ref WASM_COST_SCHEDULE: Mutex<Vec<ScheduledWasmCosts>> = Mutex::new(vec![ScheduledWasmCosts {
+        activation_height: 0,
+        costs: WasmCosts::default(),
+    }]);
+}
+
+/// Replaces the governance-configurable gas schedule. Called by the host whenever the
+/// chain's wasm gas params change (new `ScheduledWasmCosts` entries land here the same
+/// way any other on-chain param update reaches the enclave), so future lookups of
+/// [`wasm_costs_for_height`] see the new table without an enclave upgrade.
+pub fn set_wasm_cost_schedule(schedule: Vec<ScheduledWasmCosts>) {
+    *WASM_COST_SCHEDULE.lock().unwrap() = schedule;
+}
+
+/// The governance-configurable gas schedule, ordered by ascending `activation_height`.
+/// Populated from on-chain params via [`set_wasm_cost_schedule`]; falls back to
+/// [`WasmCosts::default`] when empty or when `height` predates the earliest scheduled
+/// entry.
+pub fn wasm_costs_for_height(height: u64) -> WasmCosts {
+    wasm_costs_for_height_in(&WASM_COST_SCHEDULE.lock().unwrap(), height)
+}
+
+fn wasm_costs_for_height_in(schedule: &[ScheduledWasmCosts], height: u64) -> WasmCosts {
+    schedule
+        .iter()
+        .filter(|entry| entry.activation_height <= height)
+        .max_by_key(|entry| entry.activation_height)
+        .map(|entry| entry.costs)
+        .unwrap_or_else(WasmCosts::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn costs(regular: u32) -> WasmCosts {
+        WasmCosts {
+            regular,
+            ..WasmCosts::default()
+        }
+    }
+
+    #[test]
+    fn falls_back_to_default_when_schedule_is_empty() {
+        assert_eq!(wasm_costs_for_height_in(&[], 100), WasmCosts::default());
+    }
+
+    #[test]
+    fn falls_back_to_default_when_height_predates_earliest_entry() {
+        let schedule = [ScheduledWasmCosts {
+            activation_height: 100,
+            costs: costs(7),
+        }];
+
+        assert_eq!(wasm_costs_for_height_in(&schedule, 99), WasmCosts::default());
+    }
+
+    #[test]
+    fn picks_the_entry_active_at_the_given_height() {
+        let schedule = [
+            ScheduledWasmCosts {
+                activation_height: 0,
+                costs: costs(1),
+            },
+            ScheduledWasmCosts {
+                activation_height: 100,
+                costs: costs(2),
+            },
+        ];
+
+        assert_eq!(wasm_costs_for_height_in(&schedule, 50).regular, 1);
+        assert_eq!(wasm_costs_for_height_in(&schedule, 100).regular, 2);
+    }
+
+    // Regression guard for the query-vs-exec metering divergence this schedule lookup
+    // caused when a caller passed `u64::MAX` instead of the actual chain tip height: a
+    // future-scheduled entry must never be selected before its activation height arrives.
+    #[test]
+    fn does_not_select_a_not_yet_active_future_entry() {
+        let schedule = [
+            ScheduledWasmCosts {
+                activation_height: 0,
+                costs: costs(1),
+            },
+            ScheduledWasmCosts {
+                activation_height: 1_000_000,
+                costs: costs(99),
+            },
+        ];
+
+        assert_eq!(wasm_costs_for_height_in(&schedule, 500_000).regular, 1);
+    }
+}
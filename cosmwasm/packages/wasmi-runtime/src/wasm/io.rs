@@ -0,0 +1,131 @@
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use enclave_ffi_types::EnclaveError;
+
+use crate::cosmwasm::types::*;
+use crate::crypto::Ed25519PublicKey;
+
+use super::types::{IoNonce, SecretMessage};
+
+// The nonce and public key that encrypted the message which dispatched the submessage
+// a later `reply` call is for. `reply`'s output must still be encrypted toward that
+// original caller, not toward whoever relayed the reply back into the chain.
+#[derive(Serialize, Deserialize)]
+pub struct ReplyParams {
+    pub nonce: IoNonce,
+    pub user_public_key: Ed25519PublicKey,
+}
+
+// `reply_params` is round-tripped through the untrusted host between this call (where
+// we seal it, below) and the later `reply` call (where `open_reply_params` opens it), so
+// it's sealed as a `SecretMessage` rather than plain JSON: only the enclave holds the key
+// to have produced it, which is what stops the host from forging a `ReplyParams` that
+// points a future `encrypt_output` at an attacker's public key.
+fn seal_reply_params(nonce: IoNonce, user_public_key: Ed25519PublicKey) -> Result<Vec<u8>, EnclaveError> {
+    let reply_params = ReplyParams { nonce, user_public_key };
+
+    let plaintext = serde_json::to_vec(&reply_params).map_err(|err| {
+        warn!("got an error while trying to serialize reply_params into bytes: {}", err);
+        EnclaveError::FailedToSerialize
+    })?;
+
+    Ok(SecretMessage::encrypt(&plaintext)?.to_vec())
+}
+
+pub fn open_reply_params(sealed: &[u8]) -> Result<ReplyParams, EnclaveError> {
+    let sealed = SecretMessage::from_slice(sealed)?;
+    let decrypted = sealed.decrypt()?;
+
+    serde_json::from_slice(&decrypted).map_err(|err| {
+        warn!(
+            "got an error while trying to deserialize sealed reply_params into json {:?}: {}",
+            String::from_utf8_lossy(&decrypted),
+            err
+        );
+        EnclaveError::FailedToDeserialize
+    })
+}
+
+/// Re-encrypts a contract's output toward the original caller, and, for each
+/// `WasmMsg::Execute`/`Instantiate` submessage the contract dispatches, toward the
+/// contract on the other end of that call. Other `CosmosMsg::Wasm` variants are
+/// left as they are.
+///
+/// Inter-contract messages can't reuse the caller's IO key: the target contract
+/// has no way to get at it, so each outbound wasm message gets a fresh one instead.
+pub fn encrypt_output(
+    output: Vec<u8>,
+    nonce: IoNonce,
+    user_public_key: Ed25519PublicKey,
+    contract_addr: &CanonicalAddr,
+) -> Result<Vec<u8>, EnclaveError> {
+    let mut output: Output = serde_json::from_slice(&output).map_err(|err| {
+        warn!(
+            "got an error while trying to deserialize output bytes into json {:?}: {}",
+            String::from_utf8_lossy(&output),
+            err
+        );
+        EnclaveError::FailedToDeserialize
+    })?;
+
+    if let Output::Ok(ref mut ok) = output {
+        for sub_msg in ok.messages.iter_mut() {
+            encrypt_wasm_msg(&mut sub_msg.msg, contract_addr)?;
+
+            // A submessage whose result the contract wants back needs a sealed
+            // {nonce, user_public_key} attached now, while we still have the
+            // dispatching call's IO key in scope: `reply()` has no other way to learn
+            // whose key the eventual reply output must be re-encrypted toward.
+            if sub_msg.reply_on != ReplyOn::Never {
+                sub_msg.reply_params = Some(Binary(seal_reply_params(nonce, user_public_key)?));
+            }
+        }
+        for attr in ok.log.iter() {
+            trace!("output log attribute: {:?}", attr);
+        }
+    }
+
+    let encrypted_output = serde_json::to_vec(&output).map_err(|err| {
+        warn!(
+            "got an error while trying to serialize encrypted output into bytes {:?}: {}",
+            output, err
+        );
+        EnclaveError::FailedToSerialize
+    })?;
+
+    Ok(encrypted_output)
+}
+
+fn encrypt_wasm_msg(msg: &mut CosmosMsg, sender_contract_addr: &CanonicalAddr) -> Result<(), EnclaveError> {
+    match msg {
+        CosmosMsg::Wasm(WasmMsg::Execute { code_hash, msg: inner_msg, .. })
+        | CosmosMsg::Wasm(WasmMsg::Instantiate { code_hash, msg: inner_msg, .. }) => {
+            let secret_msg =
+                SecretMessage::from_base64(inner_msg.to_base64(), code_hash, sender_contract_addr)?;
+
+            *inner_msg = Binary(secret_msg.to_vec());
+        }
+        CosmosMsg::Wasm(_) => {}
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Same treatment as [`encrypt_wasm_msg`], for a `QueryRequest::Wasm` embedded in a
+/// contract's `Querier` call rather than in its response messages.
+pub fn encrypt_wasm_query(query: &mut QueryRequest, sender_contract_addr: &CanonicalAddr) -> Result<(), EnclaveError> {
+    match query {
+        QueryRequest::Wasm(WasmQuery::Smart { code_hash, msg: inner_msg, .. }) => {
+            let secret_msg =
+                SecretMessage::from_base64(inner_msg.to_base64(), code_hash, sender_contract_addr)?;
+
+            *inner_msg = Binary(secret_msg.to_vec());
+        }
+        QueryRequest::Wasm(_) => {}
+        _ => {}
+    }
+
+    Ok(())
+}
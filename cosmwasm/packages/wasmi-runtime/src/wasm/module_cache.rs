@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use enclave_ffi_types::EnclaveError;
+use lazy_static::lazy_static;
+use log::*;
+use wasmi::{Module, ModuleInstance, ModuleRef};
+
+use super::gas::WasmCosts;
+use super::runtime::imports_builder;
+use super::types::ContractCode;
+
+// Keyed by (code hash, costs) rather than code hash alone: a contract instrumented
+// under one `WasmCosts` table must not be handed back once governance schedules a new
+// table for the current height, since the cached module's injected gas charges are
+// baked in at instrumentation time.
+lazy_static! {
+    static ref MODULE_CACHE: Mutex<HashMap<([u8; 32], WasmCosts), ModuleRef>> = Mutex::new(HashMap::new());
+}
+
+/// Compiles (or returns the cached compiled instance of) `contract_code`, instrumented
+/// under `wasm_costs`. Callers go through this rather than `wasmi::Module::from_buffer`
+/// directly so the expensive parse + determinism scan + gas-metering instrumentation
+/// only happen once per (code hash, cost table) pair, not on every
+/// `init`/`handle`/`query`/`migrate`/`reply`.
+pub fn create_module_instance(contract_code: ContractCode, wasm_costs: &WasmCosts) -> Result<ModuleRef, EnclaveError> {
+    let cache_key = (contract_code.hash(), *wasm_costs);
+
+    if let Some(cached) = MODULE_CACHE.lock().unwrap().get(&cache_key) {
+        return Ok(cached.clone());
+    }
+
+    let module = Module::from_buffer(contract_code.code()).map_err(|err| {
+        warn!(
+            "got an error while trying to parse contract wasm bytes into a module: {}",
+            err
+        );
+        EnclaveError::FailedFunctionCall
+    })?;
+
+    // A contract's instructions don't change between calls, so a module that passes is
+    // cached below under (code hash, costs) and not scanned again for that key. A
+    // rejected module is not cached, so it is scanned again on every call.
+    reject_non_deterministic_wasm(&module)?;
+
+    let module = pwasm_utils::inject_gas_counter(module, &wasm_costs.clone().into()).map_err(|_| {
+        warn!("got an error while trying to instrument the contract module with gas metering");
+        EnclaveError::FailedFunctionCall
+    })?;
+    let module = pwasm_utils::stack_height::inject_limiter(module, wasm_costs.stack_height_limit).map_err(|_| {
+        warn!("got an error while trying to inject the stack-height limiter");
+        EnclaveError::FailedFunctionCall
+    })?;
+
+    let instance = ModuleInstance::new(&module, &imports_builder())
+        .map_err(|err| {
+            warn!(
+                "got an error while trying to instantiate the contract module: {}",
+                err
+            );
+            EnclaveError::FailedFunctionCall
+        })?
+        .assert_no_start();
+
+    MODULE_CACHE.lock().unwrap().insert(cache_key, instance.clone());
+
+    Ok(instance)
+}
+
+// Consensus requires every validator to get the same result from the same contract,
+// but floating-point ops aren't guaranteed bit-identical across hardware/compilers,
+// so contracts containing them are rejected outright rather than merely metered.
+// SIMD and bulk-memory instructions are rejected for the same reason: lane-wise float
+// ops and memory.copy/fill overlap semantics have historically differed across engines.
+fn reject_non_deterministic_wasm(module: &parity_wasm::elements::Module) -> Result<(), EnclaveError> {
+    let code_section = match module.code_section() {
+        Some(code_section) => code_section,
+        None => return Ok(()),
+    };
+
+    for func_body in code_section.bodies() {
+        for instruction in func_body.code().elements() {
+            if is_non_deterministic_instruction(instruction) {
+                warn!(
+                    "rejecting contract: found non-deterministic wasm instruction {:?}",
+                    instruction
+                );
+                return Err(EnclaveError::FailedFunctionCall);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn is_non_deterministic_instruction(instruction: &parity_wasm::elements::Instruction) -> bool {
+    use parity_wasm::elements::Instruction::*;
+
+    matches!(
+        instruction,
+        // floating point
+        F32Load(..)
+            | F64Load(..)
+            | F32Store(..)
+            | F64Store(..)
+            | F32Const(..)
+            | F64Const(..)
+            | F32Eq
+            | F32Ne
+            | F32Lt
+            | F32Gt
+            | F32Le
+            | F32Ge
+            | F64Eq
+            | F64Ne
+            | F64Lt
+            | F64Gt
+            | F64Le
+            | F64Ge
+            | F32Abs
+            | F32Neg
+            | F32Ceil
+            | F32Floor
+            | F32Trunc
+            | F32Nearest
+            | F32Sqrt
+            | F32Add
+            | F32Sub
+            | F32Mul
+            | F32Div
+            | F32Min
+            | F32Max
+            | F32Copysign
+            | F64Abs
+            | F64Neg
+            | F64Ceil
+            | F64Floor
+            | F64Trunc
+            | F64Nearest
+            | F64Sqrt
+            | F64Add
+            | F64Sub
+            | F64Mul
+            | F64Div
+            | F64Min
+            | F64Max
+            | F64Copysign
+            | I32TruncSF32
+            | I32TruncUF32
+            | I32TruncSF64
+            | I32TruncUF64
+            | I64TruncSF32
+            | I64TruncUF32
+            | I64TruncSF64
+            | I64TruncUF64
+            | F32ConvertSI32
+            | F32ConvertUI32
+            | F32ConvertSI64
+            | F32ConvertUI64
+            | F32DemoteF64
+            | F64ConvertSI32
+            | F64ConvertUI32
+            | F64ConvertSI64
+            | F64ConvertUI64
+            | F64PromoteF32
+            | I32ReinterpretF32
+            | I64ReinterpretF64
+            | F32ReinterpretI32
+            | F64ReinterpretI64
+            // bulk memory
+            | MemoryInit(..)
+            | MemoryCopy
+            | MemoryFill
+            | DataDrop(..)
+            | TableInit(..)
+            | TableCopy(..)
+            | TableFill(..)
+            | TableGrow(..)
+            | TableSize(..)
+            | ElemDrop(..)
+            // SIMD
+            | V128Const(..)
+            | V128Load(..)
+            | V128Store(..)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parity_wasm::elements::Instruction;
+
+    #[test]
+    fn accepts_ordinary_integer_instructions() {
+        assert!(!is_non_deterministic_instruction(&Instruction::I32Add));
+        assert!(!is_non_deterministic_instruction(&Instruction::Call(0)));
+    }
+
+    #[test]
+    fn rejects_floating_point_instructions() {
+        assert!(is_non_deterministic_instruction(&Instruction::F64Add));
+        assert!(is_non_deterministic_instruction(&Instruction::F32Const(0)));
+    }
+
+    #[test]
+    fn rejects_bulk_memory_instructions() {
+        assert!(is_non_deterministic_instruction(&Instruction::MemoryCopy));
+    }
+}
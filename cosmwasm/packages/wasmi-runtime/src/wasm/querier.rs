@@ -0,0 +1,18 @@
+use enclave_ffi_types::EnclaveError;
+
+use crate::cosmwasm::types::*;
+
+use super::io::encrypt_wasm_query;
+
+/// Runs before a contract's `Querier` request is handed off to the chain: any
+/// `QueryRequest::Wasm` aimed at another contract gets its inner `msg` encrypted the
+/// same way an outbound `WasmMsg` is in `encrypt_output`, so contract-to-contract
+/// queries stay private in transit too.
+pub fn prepare_querier_request(
+    mut query: QueryRequest,
+    querying_contract_addr: &CanonicalAddr,
+) -> Result<QueryRequest, EnclaveError> {
+    encrypt_wasm_query(&mut query, querying_contract_addr)?;
+
+    Ok(query)
+}
@@ -0,0 +1,534 @@
+use std::convert::TryInto;
+
+use log::*;
+
+use enclave_ffi_types::{Ctx, EnclaveError};
+use wasmi::{
+    Error as InterpreterError, Externals, ExternVal, FuncInstance, FuncRef, HostError,
+    ImportsBuilder, MemoryRef, ModuleImportResolver, ModuleRef, RuntimeArgs, RuntimeValue,
+    Signature, Trap, TrapKind,
+};
+
+use crate::cosmwasm::encoding::Binary;
+use crate::cosmwasm::types::{CanonicalAddr, HumanAddr, QueryRequest};
+use crate::crypto::Ed25519PublicKey;
+
+use super::contract_validation::ContractKey;
+use super::gas::WasmCosts;
+use super::querier::prepare_querier_request;
+use super::types::IoNonce;
+
+/// Which CosmWasm env/API shape the loaded contract was compiled against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CosmWasmApiVersion {
+    V010,
+    V016,
+    V1,
+}
+
+/// Which wasm export this execution is driving.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContractOperation {
+    Init,
+    Handle,
+    Query,
+    Migrate,
+    Reply,
+}
+
+impl ContractOperation {
+    fn export_name(self) -> &'static str {
+        match self {
+            ContractOperation::Init => "init",
+            ContractOperation::Handle => "handle",
+            ContractOperation::Query => "query",
+            ContractOperation::Migrate => "migrate",
+            ContractOperation::Reply => "reply",
+        }
+    }
+}
+
+// Contracts pass buffers across the wasm boundary as a pointer to one of these (the
+// same ABI their `allocate`/`deallocate` exports use): `offset` is where the bytes
+// actually live in linear memory, `length` how many of `capacity` bytes are in use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Region {
+    offset: u32,
+    capacity: u32,
+    length: u32,
+}
+
+const REGION_SIZE: usize = 12; // 3 little-endian u32s: offset, capacity, length
+
+impl Region {
+    fn to_bytes(self) -> [u8; REGION_SIZE] {
+        let mut bytes = [0u8; REGION_SIZE];
+        bytes[0..4].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.capacity.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            offset: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            capacity: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            length: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+        }
+    }
+}
+
+// Host import function indices. `HostImportResolver` (below) hands these out as the
+// `env` module's imports at instantiation time; `ContractInstance`'s `Externals` impl
+// dispatches on them at call time, once gas/memory/context are all in scope.
+const READ_DB_INDEX: usize = 0;
+const WRITE_DB_INDEX: usize = 1;
+const REMOVE_DB_INDEX: usize = 2;
+const CANONICALIZE_ADDRESS_INDEX: usize = 3;
+const HUMANIZE_ADDRESS_INDEX: usize = 4;
+const QUERY_CHAIN_INDEX: usize = 5;
+const DEBUG_PRINT_INDEX: usize = 6;
+
+/// Execution-scoped state for a single `init`/`handle`/`query`/`migrate`/`reply` call: gas
+/// metering, the contract's encryption key, and which op we're running. Split out
+/// from `Engine` so the imports/host-function glue can borrow it mutably while the
+/// wasm module itself stays behind a plain `ModuleRef`.
+pub struct ContractInstance {
+    pub context: Ctx,
+    pub gas_limit: u64,
+    pub gas_used: u64,
+    pub wasm_costs: WasmCosts,
+    pub contract_key: ContractKey,
+    pub contract_addr: CanonicalAddr,
+    pub operation: ContractOperation,
+    pub nonce: IoNonce,
+    pub user_public_key: Ed25519PublicKey,
+    pub cosmwasm_api_version: CosmWasmApiVersion,
+    module: ModuleRef,
+    memory: MemoryRef,
+}
+
+impl ContractInstance {
+    pub fn new(
+        context: Ctx,
+        module: ModuleRef,
+        gas_limit: u64,
+        wasm_costs: WasmCosts,
+        contract_key: ContractKey,
+        contract_addr: CanonicalAddr,
+        operation: ContractOperation,
+        nonce: IoNonce,
+        user_public_key: Ed25519PublicKey,
+    ) -> Result<Self, EnclaveError> {
+        let cosmwasm_api_version = detect_api_version(&module);
+        let memory = contract_memory(&module)?;
+
+        Ok(Self {
+            context,
+            gas_limit,
+            gas_used: 0,
+            wasm_costs,
+            contract_key,
+            contract_addr,
+            operation,
+            nonce,
+            user_public_key,
+            cosmwasm_api_version,
+            module,
+            memory,
+        })
+    }
+
+    /// Calls the contract's own `allocate` export, so any region the contract gets a
+    /// pointer to is always one the contract itself believes it owns.
+    fn allocate(&mut self, size: u32) -> Result<u32, EnclaveError> {
+        let module = self.module.clone();
+        let result = module
+            .invoke_export("allocate", &[RuntimeValue::I32(size as i32)], self)
+            .map_err(|err| {
+                warn!(
+                    "got an error while trying to call the contract's allocate export: {}",
+                    err
+                );
+                EnclaveError::FailedFunctionCall
+            })?;
+
+        match result {
+            Some(RuntimeValue::I32(ptr)) => Ok(ptr as u32),
+            _ => {
+                warn!("contract's allocate export did not return a pointer");
+                Err(EnclaveError::FailedFunctionCall)
+            }
+        }
+    }
+
+    fn read_region(&self, ptr: u32) -> Result<Region, EnclaveError> {
+        let bytes = self.memory.get(ptr, REGION_SIZE).map_err(|err| {
+            warn!(
+                "got an error while trying to read a region header out of contract memory: {}",
+                err
+            );
+            EnclaveError::FailedFunctionCall
+        })?;
+
+        Ok(Region::from_bytes(&bytes))
+    }
+
+    fn write_region_header(&self, ptr: u32, region: Region) -> Result<(), EnclaveError> {
+        self.memory.set(ptr, &region.to_bytes()).map_err(|err| {
+            warn!(
+                "got an error while trying to write a region header into contract memory: {}",
+                err
+            );
+            EnclaveError::FailedFunctionCall
+        })
+    }
+
+    /// Writes `data` into an already-allocated region (one the contract handed us the
+    /// pointer to), failing if it doesn't have the capacity for `data`.
+    fn write_into_region(&self, region_ptr: u32, data: &[u8]) -> Result<(), EnclaveError> {
+        let mut region = self.read_region(region_ptr)?;
+
+        if data.len() as u32 > region.capacity {
+            warn!(
+                "contract-provided region (capacity {}) is too small for {} bytes",
+                region.capacity,
+                data.len()
+            );
+            return Err(EnclaveError::FailedFunctionCall);
+        }
+
+        self.memory.set(region.offset, data).map_err(|err| {
+            warn!(
+                "got an error while trying to write {} bytes into contract memory: {}",
+                data.len(),
+                err
+            );
+            EnclaveError::FailedFunctionCall
+        })?;
+
+        region.length = data.len() as u32;
+        self.write_region_header(region_ptr, region)
+    }
+
+    /// Allocates a fresh region inside the contract's memory, copies `buffer` into it,
+    /// and returns the pointer to the region header (what contract exports take/return
+    /// in place of the buffer itself).
+    pub fn write_to_memory(&mut self, buffer: &[u8]) -> Result<u32, EnclaveError> {
+        let region_ptr = self.allocate(buffer.len() as u32)?;
+        self.write_into_region(region_ptr, buffer)?;
+        Ok(region_ptr)
+    }
+
+    /// Reads the region a contract export returned a pointer to back out as bytes.
+    pub fn extract_vector(&self, region_ptr: u32) -> Result<Vec<u8>, EnclaveError> {
+        let region = self.read_region(region_ptr)?;
+
+        self.memory.get(region.offset, region.length as usize).map_err(|err| {
+            warn!(
+                "got an error while trying to read {} bytes back out of contract memory: {}",
+                region.length, err
+            );
+            EnclaveError::FailedFunctionCall
+        })
+    }
+
+    fn host_read_db(&mut self, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        let key_ptr: u32 = args.nth::<i32>(0) as u32;
+        let key = self.extract_vector(key_ptr).map_err(host_trap)?;
+
+        // Crosses out of the enclave: the actual key-value store lives with the
+        // untrusted host, reachable only through the ocall bridge `context` wraps.
+        let value = self.context.read_db(&key).map_err(host_trap)?;
+
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(Some(RuntimeValue::I32(0))), // null region pointer == "not found"
+        };
+
+        let region_ptr = self.write_to_memory(&value).map_err(host_trap)?;
+        Ok(Some(RuntimeValue::I32(region_ptr as i32)))
+    }
+
+    fn host_write_db(&mut self, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        let key_ptr: u32 = args.nth::<i32>(0) as u32;
+        let value_ptr: u32 = args.nth::<i32>(1) as u32;
+
+        let key = self.extract_vector(key_ptr).map_err(host_trap)?;
+        let value = self.extract_vector(value_ptr).map_err(host_trap)?;
+
+        self.context.write_db(&key, &value).map_err(host_trap)?;
+        Ok(None)
+    }
+
+    fn host_remove_db(&mut self, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        let key_ptr: u32 = args.nth::<i32>(0) as u32;
+        let key = self.extract_vector(key_ptr).map_err(host_trap)?;
+
+        self.context.remove_db(&key).map_err(host_trap)?;
+        Ok(None)
+    }
+
+    fn host_canonicalize_address(&mut self, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        let human_ptr: u32 = args.nth::<i32>(0) as u32;
+        let canonical_ptr: u32 = args.nth::<i32>(1) as u32;
+
+        let human = self.extract_vector(human_ptr).map_err(host_trap)?;
+        let human = String::from_utf8(human).map_err(|_| host_trap(EnclaveError::FailedToDeserialize))?;
+
+        let canonical = CanonicalAddr::from_human(&HumanAddr(human)).map_err(|err| {
+            warn!(
+                "got an error while trying to canonicalize a contract-supplied address: {}",
+                err
+            );
+            host_trap(EnclaveError::FailedToDeserialize)
+        })?;
+
+        self.write_into_region(canonical_ptr, &(canonical.0).0).map_err(host_trap)?;
+        Ok(Some(RuntimeValue::I32(0))) // 0 == success
+    }
+
+    fn host_humanize_address(&mut self, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        let canonical_ptr: u32 = args.nth::<i32>(0) as u32;
+        let human_ptr: u32 = args.nth::<i32>(1) as u32;
+
+        let canonical_bytes = self.extract_vector(canonical_ptr).map_err(host_trap)?;
+        let canonical = CanonicalAddr(Binary(canonical_bytes));
+
+        let human = canonical.to_human().map_err(|err| {
+            warn!(
+                "got an error while trying to humanize a contract-supplied address: {}",
+                err
+            );
+            host_trap(EnclaveError::FailedToDeserialize)
+        })?;
+
+        self.write_into_region(human_ptr, human.0.as_bytes()).map_err(host_trap)?;
+        Ok(Some(RuntimeValue::I32(0))) // 0 == success
+    }
+
+    fn host_query_chain(&mut self, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        let request_ptr: u32 = args.nth::<i32>(0) as u32;
+        let request_bytes = self.extract_vector(request_ptr).map_err(host_trap)?;
+
+        let query: QueryRequest = serde_json::from_slice(&request_bytes).map_err(|err| {
+            warn!(
+                "got an error while trying to deserialize a contract's query_chain request: {}",
+                err
+            );
+            host_trap(EnclaveError::FailedToDeserialize)
+        })?;
+
+        let response = query_chain(&self.context, &self.contract_addr, query).map_err(host_trap)?;
+
+        let region_ptr = self.write_to_memory(&response.0).map_err(host_trap)?;
+        Ok(Some(RuntimeValue::I32(region_ptr as i32)))
+    }
+
+    fn host_debug_print(&mut self, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        let msg_ptr: u32 = args.nth::<i32>(0) as u32;
+        let message = self.extract_vector(msg_ptr).map_err(host_trap)?;
+
+        debug!("contract debug_print: {}", String::from_utf8_lossy(&message));
+        Ok(None)
+    }
+}
+
+impl Externals for ContractInstance {
+    fn invoke_index(&mut self, index: usize, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        match index {
+            READ_DB_INDEX => self.host_read_db(args),
+            WRITE_DB_INDEX => self.host_write_db(args),
+            REMOVE_DB_INDEX => self.host_remove_db(args),
+            CANONICALIZE_ADDRESS_INDEX => self.host_canonicalize_address(args),
+            HUMANIZE_ADDRESS_INDEX => self.host_humanize_address(args),
+            QUERY_CHAIN_INDEX => self.host_query_chain(args),
+            DEBUG_PRINT_INDEX => self.host_debug_print(args),
+            _ => panic!("contract called an unknown host function index {}", index),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HostFunctionError(EnclaveError);
+
+impl std::fmt::Display for HostFunctionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "host function call failed: {:?}", self.0)
+    }
+}
+
+impl HostError for HostFunctionError {}
+
+fn host_trap(err: EnclaveError) -> Trap {
+    Trap::new(TrapKind::Host(Box::new(HostFunctionError(err))))
+}
+
+fn detect_api_version(module: &ModuleRef) -> CosmWasmApiVersion {
+    if module.export_by_name("cosmwasm_api_1_0").is_some() {
+        CosmWasmApiVersion::V1
+    } else if module.export_by_name("cosmwasm_api_0_16").is_some() {
+        CosmWasmApiVersion::V016
+    } else {
+        CosmWasmApiVersion::V010
+    }
+}
+
+// Contracts export their own linear memory (rather than importing the host's), so it's
+// read straight off the instantiated module once, up front.
+fn contract_memory(module: &ModuleRef) -> Result<MemoryRef, EnclaveError> {
+    match module.export_by_name("memory") {
+        Some(ExternVal::Memory(memory)) => Ok(memory),
+        _ => {
+            warn!("contract module does not export its linear memory");
+            Err(EnclaveError::FailedFunctionCall)
+        }
+    }
+}
+
+/// Drives a loaded contract module's exports for a single execution.
+pub struct Engine {
+    pub contract_instance: ContractInstance,
+    module: ModuleRef,
+}
+
+impl Engine {
+    pub fn new(contract_instance: ContractInstance, module: ModuleRef) -> Self {
+        Self {
+            contract_instance,
+            module,
+        }
+    }
+
+    pub fn init(&mut self, env_ptr: i32, msg_ptr: i32) -> Result<i32, EnclaveError> {
+        self.call_export(ContractOperation::Init.export_name(), env_ptr, msg_ptr)
+    }
+
+    pub fn handle(&mut self, env_ptr: i32, msg_ptr: i32) -> Result<i32, EnclaveError> {
+        self.call_export(ContractOperation::Handle.export_name(), env_ptr, msg_ptr)
+    }
+
+    pub fn migrate(&mut self, env_ptr: i32, msg_ptr: i32) -> Result<i32, EnclaveError> {
+        self.call_export(ContractOperation::Migrate.export_name(), env_ptr, msg_ptr)
+    }
+
+    pub fn reply(&mut self, env_ptr: i32, reply_ptr: i32) -> Result<i32, EnclaveError> {
+        self.call_export(ContractOperation::Reply.export_name(), env_ptr, reply_ptr)
+    }
+
+    pub fn query(&mut self, msg_ptr: i32) -> Result<i32, EnclaveError> {
+        self.invoke("query", &[RuntimeValue::I32(msg_ptr)])
+    }
+
+    fn call_export(&mut self, name: &str, env_ptr: i32, msg_ptr: i32) -> Result<i32, EnclaveError> {
+        self.invoke(name, &[RuntimeValue::I32(env_ptr), RuntimeValue::I32(msg_ptr)])
+    }
+
+    fn invoke(&mut self, name: &str, args: &[RuntimeValue]) -> Result<i32, EnclaveError> {
+        let result = self
+            .module
+            .invoke_export(name, args, &mut self.contract_instance)
+            .map_err(|err| {
+                warn!("got an error while trying to invoke {}: {}", name, err);
+                EnclaveError::FailedFunctionCall
+            })?;
+
+        match result {
+            Some(RuntimeValue::I32(ptr)) => Ok(ptr),
+            _ => {
+                warn!("{} did not return a pointer", name);
+                Err(EnclaveError::FailedFunctionCall)
+            }
+        }
+    }
+
+    pub fn write_to_memory(&mut self, buffer: &[u8]) -> Result<i32, EnclaveError> {
+        self.contract_instance.write_to_memory(buffer).map(|ptr| ptr as i32)
+    }
+
+    pub fn extract_vector(&mut self, vec_ptr_ptr: i32) -> Result<Vec<u8>, EnclaveError> {
+        self.contract_instance.extract_vector(vec_ptr_ptr as u32)
+    }
+
+    pub fn gas_used(&self) -> u64 {
+        self.contract_instance.gas_used
+    }
+}
+
+struct HostImportResolver;
+
+impl ModuleImportResolver for HostImportResolver {
+    fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, InterpreterError> {
+        let index = match field_name {
+            "read_db" => READ_DB_INDEX,
+            "write_db" => WRITE_DB_INDEX,
+            "remove_db" => REMOVE_DB_INDEX,
+            "canonicalize_address" => CANONICALIZE_ADDRESS_INDEX,
+            "humanize_address" => HUMANIZE_ADDRESS_INDEX,
+            "query_chain" => QUERY_CHAIN_INDEX,
+            "debug_print" => DEBUG_PRINT_INDEX,
+            _ => {
+                return Err(InterpreterError::Instantiation(format!(
+                    "contract imports unknown host function \"{}\"",
+                    field_name
+                )));
+            }
+        };
+
+        Ok(FuncInstance::alloc_host(signature.clone(), index))
+    }
+}
+
+// `HostImportResolver` is stateless (it only maps names to indices; `ContractInstance`'s
+// `Externals` impl is what actually runs them), so one shared instance covers every
+// contract instantiation.
+lazy_static::lazy_static! {
+    static ref HOST_IMPORT_RESOLVER: HostImportResolver = HostImportResolver;
+}
+
+pub(crate) fn imports_builder() -> ImportsBuilder<'static> {
+    ImportsBuilder::new().with_resolver("env", &*HOST_IMPORT_RESOLVER)
+}
+
+/// Host-side implementation of the `query_chain` import a contract's `Querier` calls
+/// into while it's running: encrypts the inner wasm query toward its target (same as
+/// an outbound `WasmMsg`), dispatches it to the chain through the untrusted host, and
+/// returns the (still-encrypted) response bytes the contract's `Querier` decodes.
+pub fn query_chain(
+    context: &Ctx,
+    querying_contract_addr: &CanonicalAddr,
+    query: QueryRequest,
+) -> Result<Binary, EnclaveError> {
+    let query = prepare_querier_request(query, querying_contract_addr)?;
+
+    let request_bytes = serde_json::to_vec(&query).map_err(|err| {
+        warn!(
+            "got an error while trying to serialize a query_chain request into bytes: {}",
+            err
+        );
+        EnclaveError::FailedToSerialize
+    })?;
+
+    let response_bytes = context.query_chain(&request_bytes).map_err(|err| {
+        warn!("got an error while dispatching query_chain to the host: {:?}", err);
+        EnclaveError::FailedFunctionCall
+    })?;
+
+    Ok(Binary(response_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Region;
+
+    #[test]
+    fn region_round_trips_through_bytes() {
+        let region = Region {
+            offset: 0x1000,
+            capacity: 64,
+            length: 17,
+        };
+
+        assert_eq!(Region::from_bytes(&region.to_bytes()), region);
+    }
+}